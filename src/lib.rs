@@ -0,0 +1,378 @@
+//! Core low-complexity sequence analysis for `komplexity`.
+//!
+//! This crate exposes the k-mer/SDUST scoring and masking logic that the
+//! `kz` binary wraps with argument parsing and FASTA/FASTQ I/O, so other
+//! tools can call it directly on in-memory sequences.
+
+extern crate bio;
+extern crate fnv;
+
+use std::collections::VecDeque;
+use std::iter;
+use std::sync::OnceLock;
+
+use bio::alphabets;
+use bio::alphabets::RankTransform;
+
+use fnv::{FnvHashSet, FnvHashMap};
+
+/// A half-open, 0-based `[start, end)` range of a sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interval {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// How a masked low-complexity region is represented in the output sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaskType {
+    /// Replace the region with `N`.
+    N,
+    /// Lower-case the region in place.
+    LowerCase,
+}
+
+/// Which scoring algorithm `low_complexity_intervals`/`mask_sequence` use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    /// Ratio of distinct k-mers to window length (the original masker).
+    KmerRatio,
+    /// Symmetric DUST score over overlapping triplets.
+    Sdust,
+}
+
+/// Parameters for `low_complexity_intervals` and `mask_sequence`.
+/// `unique_kmers` and `complexity_score` take their k-mer length as a
+/// separate `k` argument rather than a `ComplexityOptions`.
+#[derive(Debug, Clone, Copy)]
+pub struct ComplexityOptions {
+    /// Length of k-mer used by `Algorithm::KmerRatio`.
+    pub k: u32,
+    /// Complexity threshold for `Algorithm::KmerRatio`, in `[0, 1]`: a
+    /// window is low-complexity when its distinct-kmer ratio is below this.
+    pub threshold: f64,
+    /// Sliding window size, in k-mers/triplets, used by both algorithms.
+    pub window_size: usize,
+    /// How masked regions are represented in `mask_sequence`'s output.
+    pub mask_type: MaskType,
+    /// Which scoring algorithm to use for interval detection.
+    pub algorithm: Algorithm,
+    /// SDUST score threshold for `Algorithm::Sdust`: a window is
+    /// low-complexity when its score exceeds this.
+    pub sdust_threshold: f64,
+}
+
+impl Default for ComplexityOptions {
+    fn default() -> Self {
+        ComplexityOptions {
+            k: 4,
+            threshold: 0.55,
+            window_size: 32,
+            mask_type: MaskType::N,
+            algorithm: Algorithm::KmerRatio,
+            sdust_threshold: 2.0,
+        }
+    }
+}
+
+/// Largest k-mer length supported: because we use the extended IUPAC
+/// alphabet, larger `k` would overflow the rank-transform encoding used
+/// to pack a k-mer into a `usize`.
+const MAX_K: u32 = 12;
+
+/// The IUPAC rank transform is the same for every call, so build it once
+/// and share it: reconstructing it per record would redo the same
+/// lookup-table work on every one of potentially millions of calls from
+/// `kz`'s worker pool.
+fn rank_transform() -> &'static RankTransform {
+    static RANK_TRANSFORM: OnceLock<RankTransform> = OnceLock::new();
+    RANK_TRANSFORM.get_or_init(|| {
+        let alphabet = alphabets::dna::iupac_alphabet();
+        RankTransform::new(&alphabet)
+    })
+}
+
+fn check_k(k: u32) {
+    assert!(k <= MAX_K, "k must be <= {} (komplexity uses the extended IUPAC alphabet, which restricts k-mer length)", MAX_K);
+}
+
+/// Number of distinct k-mers of length `k` in `seq`.
+///
+/// # Panics
+///
+/// Panics if `k > 12`.
+pub fn unique_kmers(seq: &[u8], k: u32) -> usize {
+    check_k(k);
+    let rank = rank_transform();
+    unique_kmers_with_rank(seq, k, rank)
+}
+
+fn unique_kmers_with_rank(text: &[u8], k: u32, rank: &RankTransform) -> usize {
+    rank.qgrams(k, text)
+        .collect::<FnvHashSet<usize>>()
+        .len()
+}
+
+/// Ratio of distinct k-mers to sequence length: 0 is least complex (most
+/// repetitive), approaching 1 is most complex (every k-mer distinct).
+///
+/// # Panics
+///
+/// Panics if `k > 12`.
+pub fn complexity_score(seq: &[u8], k: u32) -> f64 {
+    let kmers = unique_kmers(seq, k);
+    kmers as f64 / seq.len() as f64
+}
+
+/// Computes the collapsed low-complexity intervals in `seq` using
+/// whichever scoring algorithm `options.algorithm` selects.
+///
+/// # Panics
+///
+/// Panics if `options.k > 12` and `options.algorithm` is `Algorithm::KmerRatio`.
+pub fn low_complexity_intervals(seq: &[u8], options: &ComplexityOptions) -> Vec<Interval> {
+    let rank = rank_transform();
+    match options.algorithm {
+        Algorithm::KmerRatio => {
+            check_k(options.k);
+            lc2(seq, options.k, rank, options.threshold, options.window_size)
+        },
+        Algorithm::Sdust => sdust(seq, rank, options.sdust_threshold, options.window_size),
+    }
+}
+
+/// Masks the low-complexity regions of `seq` per `options`, leaving the
+/// rest of the sequence untouched.
+///
+/// # Panics
+///
+/// Panics if `options.k > 12` and `options.algorithm` is `Algorithm::KmerRatio`.
+pub fn mask_sequence(seq: &[u8], options: &ComplexityOptions) -> Vec<u8> {
+    let intervals = low_complexity_intervals(seq, options);
+    mask_intervals(seq, intervals, &options.mask_type)
+}
+
+fn lc2(text: &[u8], q: u32, rank: &RankTransform, threshold: f64, window_size: usize) -> Vec<Interval> {
+    // `q` is bounds-checked by the caller (`check_k`)
+    let q = q as usize;
+
+    let mut intervals: Vec<Interval> = Vec::new();
+    let mut window: VecDeque<usize> = VecDeque::with_capacity(window_size);
+    let mut kmer_iterator = rank.qgrams(q as u32, text);
+    let mut kmers: FnvHashMap<usize, usize> = FnvHashMap::default();
+
+    // Init: fill window buffer
+    for _ in 0..window_size {
+        match kmer_iterator.next() {
+            Some(kmer) => window.push_back(kmer),
+            None => break
+        }
+    }
+    // Count kmers in window
+    for kmer in window.iter() {
+        let n = kmers.entry(*kmer).or_insert(0);
+        *n += 1;
+    }
+
+    let mut idx = 0;
+    loop {
+        let window_complexity = kmers.len() as f64 / window.len() as f64;
+        if window_complexity < threshold {
+            let start = idx;
+            let end = idx + (window.len() - 1 + q);
+            intervals.push(Interval{ start, end });
+        }
+        match kmer_iterator.next() {
+            Some(kmer) => {
+                let prev = window.pop_front().unwrap();
+                window.push_back(kmer);
+                // Update kmer counts: remove 1 from leaving kmer
+                let prev_n = *kmers.get(&prev).unwrap();
+                if prev_n == 1 {
+                    kmers.remove(&prev);
+                } else {
+                    kmers.insert(prev, prev_n-1);
+                }
+                // Update kmer counts: add 1 for entering kmer
+                let next_n = kmers.entry(kmer).or_insert(0);
+                *next_n += 1;
+                // Update index
+                idx += 1;
+            },
+            None => break,
+        }
+    }
+    collapse_intervals(intervals)
+}
+
+/// Symmetric DUST (SDUST) scoring: for a sliding window of overlapping
+/// triplets, tally each triplet's count `c_t` and score the window as
+/// `S = (sum(c_t*(c_t-1)/2)) / (L-1)`, where `L` is the number of
+/// triplets in the window. A window scoring above `threshold` is called
+/// low-complexity; adjacent/overlapping flagged windows are merged into
+/// maximal intervals by `collapse_intervals`, matching `lc2`'s approach.
+/// Sequences too short to hold a single triplet are left unmasked.
+fn sdust(text: &[u8], rank: &RankTransform, threshold: f64, window_size: usize) -> Vec<Interval> {
+    const Q: usize = 3;
+
+    if text.len() < Q {
+        return Vec::new();
+    }
+
+    let mut intervals: Vec<Interval> = Vec::new();
+    let mut window: VecDeque<usize> = VecDeque::with_capacity(window_size);
+    let mut triplet_iterator = rank.qgrams(Q as u32, text);
+    let mut counts: FnvHashMap<usize, usize> = FnvHashMap::default();
+
+    // Init: fill window buffer
+    for _ in 0..window_size {
+        match triplet_iterator.next() {
+            Some(triplet) => window.push_back(triplet),
+            None => break
+        }
+    }
+    // Count triplets in window
+    for triplet in window.iter() {
+        let n = counts.entry(*triplet).or_insert(0);
+        *n += 1;
+    }
+
+    let mut idx = 0;
+    loop {
+        let l = window.len();
+        if l > 1 {
+            let sum: usize = counts.values().map(|c| c * (c - 1) / 2).sum();
+            let score = sum as f64 / (l - 1) as f64;
+            if score > threshold {
+                let start = idx;
+                let end = idx + (l - 1 + Q);
+                intervals.push(Interval{ start, end });
+            }
+        }
+        match triplet_iterator.next() {
+            Some(triplet) => {
+                let prev = window.pop_front().unwrap();
+                window.push_back(triplet);
+                // Update triplet counts: remove 1 from leaving triplet
+                let prev_n = *counts.get(&prev).unwrap();
+                if prev_n == 1 {
+                    counts.remove(&prev);
+                } else {
+                    counts.insert(prev, prev_n-1);
+                }
+                // Update triplet counts: add 1 for entering triplet
+                let next_n = counts.entry(triplet).or_insert(0);
+                *next_n += 1;
+                // Update index
+                idx += 1;
+            },
+            None => break,
+        }
+    }
+    collapse_intervals(intervals)
+}
+
+fn collapse_intervals(intervals: Vec<Interval>) -> Vec<Interval> {
+    let mut collapsed: Vec<Interval> = Vec::new();
+    let mut intervals = intervals.into_iter();
+    if let Some(mut current) = intervals.next() {
+        for interval in intervals {
+            if interval.start < current.end {
+                current.end = interval.end;
+            } else {
+                collapsed.push(current);
+                current = interval;
+            }
+        }
+        collapsed.push(current);
+    }
+    collapsed
+}
+
+fn mask_intervals(seq: &[u8], intervals: Vec<Interval>, mask_type: &MaskType) -> Vec<u8> {
+    let mut new_seq: Vec<u8> = Vec::with_capacity(seq.len());
+    let mut last = Interval{start: 0, end: 0};
+    for interval in intervals {
+        let intervening = &seq[last.end .. interval.start];
+        new_seq.extend_from_slice(intervening);
+        match *mask_type {
+            MaskType::LowerCase => new_seq.append(&mut lowercase(&seq[interval.start..interval.end])),
+            MaskType::N => new_seq.extend(iter::repeat_n(b'N', interval.end-interval.start)),
+        };
+        last = interval;
+    }
+    let end = &seq[last.end..];
+    new_seq.extend_from_slice(end);
+    new_seq
+}
+
+fn lowercase(seq: &[u8]) -> Vec<u8> {
+    // ACGTRYSWKMBDHVNZ
+    let mut new = Vec::with_capacity(seq.len());
+    for b in seq {
+        let b = match *b {
+            b'A' => b'a',
+            b'C' => b'c',
+            b'G' => b'g',
+            b'T' => b't',
+            b'R' => b'r',
+            b'Y' => b'y',
+            b'S' => b's',
+            b'W' => b'w',
+            b'K' => b'k',
+            b'M' => b'm',
+            b'B' => b'b',
+            b'D' => b'd',
+            b'H' => b'h',
+            b'V' => b'v',
+            b'N' => b'n',
+            b'Z' => b'z',
+            _ => *b,
+        };
+        new.push(b);
+    }
+    new
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sdust_scores_a_fully_repetitive_window() {
+        // 10 A's -> one window of 8 overlapping "AAA" triplets, all
+        // identical: S = (8*7/2) / (8-1) = 28/7 = 4.0.
+        let seq = b"AAAAAAAAAA";
+        let rank = rank_transform();
+        assert_eq!(sdust(seq, rank, 3.99, 20), vec![Interval { start: 0, end: 10 }]);
+        assert!(sdust(seq, rank, 4.0, 20).is_empty());
+    }
+
+    #[test]
+    fn sdust_does_not_flag_a_window_of_distinct_triplets() {
+        // "ACGTA" -> 3 distinct triplets ("ACG", "CGT", "GTA"), each
+        // counted once: S = 0.
+        let seq = b"ACGTA";
+        assert!(sdust(seq, rank_transform(), 0.0, 20).is_empty());
+    }
+
+    #[test]
+    fn sdust_leaves_too_short_sequences_unmasked() {
+        let rank = rank_transform();
+        assert!(sdust(b"AC", rank, 0.0, 20).is_empty());
+        assert!(sdust(b"", rank, 0.0, 20).is_empty());
+    }
+
+    #[test]
+    fn collapse_intervals_merges_overlapping_ranges() {
+        let intervals = vec![
+            Interval { start: 0, end: 5 },
+            Interval { start: 3, end: 8 },
+            Interval { start: 10, end: 12 },
+        ];
+        assert_eq!(
+            collapse_intervals(intervals),
+            vec![Interval { start: 0, end: 8 }, Interval { start: 10, end: 12 }],
+        );
+    }
+}
@@ -1,20 +1,27 @@
 extern crate bio;
 extern crate clap;
 extern crate fnv;
+extern crate flate2;
+extern crate komplexity;
 
-use std::vec::Vec;
 use std::io;
-use std::iter;
-use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::fs::File;
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
 
-use bio::alphabets;
-use bio::alphabets::RankTransform;
 use bio::io::{fastq, fasta};
 
-use fnv::{FnvHashSet, FnvHashMap};
+use fnv::FnvHashMap;
+
+use flate2::Compression;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
 
 use clap::{App, Arg};
 
+use komplexity::{Algorithm, ComplexityOptions, Interval, MaskType};
+
 fn main() {
 
     let args = App::new("kz")
@@ -45,12 +52,46 @@ fn main() {
             .long("mask")
             .short("m")
             .takes_value(false)
+            .conflicts_with("bed")
             .help("use sliding window to mask low-complexity regions"))
+        .arg(Arg::with_name("bed")
+            .long("bed")
+            .takes_value(false)
+            .conflicts_with("mask")
+            .help("output collapsed low-complexity regions as BED3 intervals instead of masking"))
         .arg(Arg::with_name("lower_case")
             .long("lower_case")
             .short("l")
             .takes_value(false)
             .help("mask using lower-case symbols rather than Ns"))
+        .arg(Arg::with_name("threads")
+            .long("threads")
+            .short("j")
+            .takes_value(true)
+            .default_value("1")
+            .help("number of worker threads to use for parallel record processing"))
+        .arg(Arg::with_name("output")
+            .long("output")
+            .short("o")
+            .takes_value(true)
+            .help("output file (defaults to stdout); a '.gz' extension implies --gzip"))
+        .arg(Arg::with_name("gzip")
+            .long("gzip")
+            .short("z")
+            .takes_value(false)
+            .help("gzip-compress the output, regardless of the output file extension"))
+        .arg(Arg::with_name("algorithm")
+            .long("algorithm")
+            .short("a")
+            .takes_value(true)
+            .possible_values(&["kmer", "sdust"])
+            .default_value("kmer")
+            .help("low-complexity scoring algorithm: 'kmer' (distinct k-mer ratio) or 'sdust' (symmetric DUST score)"))
+        .arg(Arg::with_name("sdust_threshold")
+            .long("sdust-threshold")
+            .takes_value(true)
+            .default_value("2.0")
+            .help("SDUST score threshold above which a window is called low-complexity (only used with --algorithm sdust)"))
     .get_matches();
 
     let record_type = match args.is_present("fasta") {
@@ -58,9 +99,12 @@ fn main() {
         false => RecordType::Fastq,
     };
 
-    let task = match args.is_present("mask") {
-        true => Task::Mask,
-        false => Task::Measure
+    let task = if args.is_present("bed") {
+        Task::Bed
+    } else if args.is_present("mask") {
+        Task::Mask
+    } else {
+        Task::Measure
     };
 
     let mask_type = match args.is_present("lower_case") {
@@ -75,23 +119,17 @@ fn main() {
         .parse()
         .expect("k must be an integer");
 
-    if k > 12 {
-        // Because we use the extended IUPAC alphabet, we're restricted to
-        // smaller ks (though this doesn't matter for our purposes)
-        error_exit("-k must be less than or equal to 12")
-    }
-
     let threshold: f64 = args
         .value_of("threshold")
         .unwrap()
         .trim()
         .parse()
         .expect("'--threshold' must be a number between 0-1");
-    
-    if threshold < 0.0 || threshold > 1.0 {
+
+    if !(0.0..=1.0).contains(&threshold) {
         error_exit("'--threshold' must be a number between 0-1");
     }
-    
+
     let window_size: usize = args
         .value_of("window_size")
         .unwrap()
@@ -99,13 +137,43 @@ fn main() {
         .parse()
         .expect("'--window_size' must be an integer greater than 0");
 
-    complexity(record_type, task, k, threshold, window_size, mask_type);
-}
+    let algorithm = match args.value_of("algorithm").unwrap() {
+        "sdust" => Algorithm::Sdust,
+        _ => Algorithm::KmerRatio,
+    };
+
+    if algorithm == Algorithm::KmerRatio && k > 12 {
+        // Because we use the extended IUPAC alphabet, we're restricted to
+        // smaller ks (though this doesn't matter for our purposes). SDUST
+        // always scores fixed-length triplets, so this bound doesn't apply
+        // to it.
+        error_exit("-k must be less than or equal to 12")
+    }
+
+    let sdust_threshold: f64 = args
+        .value_of("sdust_threshold")
+        .unwrap()
+        .trim()
+        .parse()
+        .expect("'--sdust-threshold' must be a number");
 
-#[derive(Debug)]
-struct Interval {
-    start: usize,
-    end: usize
+    let threads: usize = args
+        .value_of("threads")
+        .unwrap()
+        .trim()
+        .parse()
+        .expect("'--threads' must be a positive integer");
+
+    if threads < 1 {
+        error_exit("'--threads' must be at least 1");
+    }
+
+    let output = args.value_of("output").map(|s| s.to_string());
+    let gzip = args.is_present("gzip") || output.as_ref().is_some_and(|path| path.ends_with(".gz"));
+
+    let options = ComplexityOptions { k, threshold, window_size, mask_type, algorithm, sdust_threshold };
+
+    complexity(record_type, task, options, threads, output, gzip);
 }
 
 enum RecordType {
@@ -113,169 +181,243 @@ enum RecordType {
     Fastq
 }
 
+#[derive(Clone, Copy)]
 enum Task {
     Mask,
-    Measure
+    Measure,
+    Bed
 }
 
-enum MaskType {
-    N,
-    LowerCase
+/// Per-record result, computed on a worker thread and handed back to the
+/// writer thread alongside the original record (so ids/descs/quals are
+/// still available without having to clone them up front).
+enum RecordOutput {
+    Masked(Vec<u8>),
+    Measured(String),
+    Bed(Vec<Interval>),
 }
 
-fn complexity(record_type: RecordType, task: Task, k: u32, threshold: f64, window_size: usize, mask_type: MaskType) {
-    let alphabet = alphabets::dna::iupac_alphabet();
-    let rank = RankTransform::new(&alphabet);
-    
+fn complexity(record_type: RecordType, task: Task, options: ComplexityOptions, threads: usize, output: Option<String>, gzip: bool) {
+    let reader = open_reader();
+    let mut out = open_writer(output.as_deref(), gzip);
+
     match record_type {
         RecordType::Fasta => {
-            let mut writer = fasta::Writer::new(io::stdout());
-            let records = fasta::Reader::new(io::stdin()).records();
-            records
-                .map(|r| r.expect("Error reading FASTA record"))
-                .map(|r| {
-                    let id = r.id();
-                    let seq = r.seq();
-                    match task {
-                        Task::Mask => {
-                            let seq = mask_sequence(seq, &rank, k, threshold, window_size, &mask_type);
-                            writer.write(id, r.desc(), &seq).unwrap();
-                        },
-                        Task::Measure => {
-                            let length = seq.len();
-                            let kmers = unique_kmers(seq, k, &rank);
-                            println!("{}\t{}\t{}\t{}", id, length, kmers, kmers as f64 / length as f64);
-                        }
-                    } 
-                    
-                })
-                .collect::<Vec<()>>();
-        }, 
+            let records = fasta::Reader::new(reader).records()
+                .map(|r| r.expect("Error reading FASTA record"));
+            match task {
+                Task::Mask => {
+                    let mut writer = fasta::Writer::new(&mut out);
+                    process_records(records, threads, task, options,
+                        |record, output| match output {
+                            RecordOutput::Masked(seq) => writer.write(record.id(), record.desc(), &seq).unwrap(),
+                            _ => unreachable!("Task::Mask only ever produces RecordOutput::Masked"),
+                        });
+                },
+                Task::Measure | Task::Bed => {
+                    process_records(records, threads, task, options,
+                        |record, output| match output {
+                            RecordOutput::Measured(line) => writeln!(out, "{}", line).unwrap(),
+                            RecordOutput::Bed(intervals) => write_bed(&mut out, record.id(), &intervals),
+                            _ => unreachable!("Task::Measure/Task::Bed never produce RecordOutput::Masked"),
+                        });
+                },
+            }
+        },
         RecordType::Fastq => {
-            let mut writer = fastq::Writer::new(io::stdout());
-            let records = fastq::Reader::new(io::stdin()).records();
-            records
-                .map(|r| r.expect("Error reading FASTQ record"))
-                .map(|r| {
-                    let id = r.id();
-                    let seq = r.seq();
-                    match task {
-                        Task::Mask => {
-                            let seq = mask_sequence(seq, &rank, k, threshold, window_size, &mask_type);
-                            writer.write(id, r.desc(), &seq, r.qual()).unwrap();
-                        },
-                        Task::Measure => {
-                            let length = seq.len();
-                            let kmers = unique_kmers(seq, k, &rank);
-                            println!("{}\t{}\t{}\t{}", id, length, kmers, kmers as f64 / length as f64);
-                        }
-                    } 
-                })
-                .collect::<Vec<()>>();
+            let records = fastq::Reader::new(reader).records()
+                .map(|r| r.expect("Error reading FASTQ record"));
+            match task {
+                Task::Mask => {
+                    let mut writer = fastq::Writer::new(&mut out);
+                    process_records(records, threads, task, options,
+                        |record, output| match output {
+                            RecordOutput::Masked(seq) => writer.write(record.id(), record.desc(), &seq, record.qual()).unwrap(),
+                            _ => unreachable!("Task::Mask only ever produces RecordOutput::Masked"),
+                        });
+                },
+                Task::Measure | Task::Bed => {
+                    process_records(records, threads, task, options,
+                        |record, output| match output {
+                            RecordOutput::Measured(line) => writeln!(out, "{}", line).unwrap(),
+                            RecordOutput::Bed(intervals) => write_bed(&mut out, record.id(), &intervals),
+                            _ => unreachable!("Task::Measure/Task::Bed never produce RecordOutput::Masked"),
+                        });
+                },
+            }
         }
     }
+
+    // Explicitly finalize the output stream (writing the gzip footer, if
+    // any) so a failed flush surfaces as a panic instead of being silently
+    // swallowed by `Drop`.
+    out.finish().expect("error finalizing output stream");
 }
 
-fn mask_sequence(seq: &[u8], rank: &RankTransform, k: u32, threshold: f64, window_size: usize, mask_type: &MaskType) -> Vec<u8> {
-    // let intervals = lc_intervals(seq, k, rank, threshold, window_size);
-    let intervals = lc2(seq, k, rank, threshold, window_size);
-    mask_intervals(seq, intervals, mask_type)
+/// Sniffs the first two bytes of stdin for the gzip magic number
+/// (`0x1f 0x8b`) and transparently wraps the reader in a gzip decoder when
+/// present, so callers can pipe in either plain or gzip-compressed input.
+/// Uses `MultiGzDecoder` rather than `GzDecoder` since bgzip/pigz output
+/// (and BGZF in particular) concatenates multiple gzip members, and a
+/// plain `GzDecoder` would silently stop after the first one.
+fn open_reader() -> Box<dyn Read + Send> {
+    let mut stdin = io::BufReader::new(io::stdin());
+    let mut magic = [0u8; 2];
+    let n = stdin.read(&mut magic).unwrap_or(0);
+    let chained = io::Cursor::new(magic[..n].to_vec()).chain(stdin);
+    if n == 2 && magic == [0x1f, 0x8b] {
+        Box::new(MultiGzDecoder::new(chained))
+    } else {
+        Box::new(chained)
+    }
 }
 
-fn unique_kmers(text: &[u8], k: u32, rank: &RankTransform) -> usize {
-    rank.qgrams(k, text)
-        .collect::<FnvHashSet<usize>>()
-        .len()
+/// Output stream for `complexity()`: either a raw sink or one wrapped in a
+/// gzip encoder. Unlike a bare `Box<dyn Write>`, this exposes an explicit
+/// `finish()` so the gzip footer write (and any I/O error from it) isn't
+/// left to `Drop`, which discards errors.
+enum OutputSink {
+    Plain(Box<dyn Write>),
+    Gzip(GzEncoder<Box<dyn Write>>),
 }
 
-fn lc2(text: &[u8], q: u32, rank: &RankTransform, threshold: f64, window_size: usize) -> Vec<Interval> {
-    // Bounds checking
-    let q = q as usize;
-
-    let mut intervals: Vec<Interval> = Vec::new();
-    let mut window: VecDeque<usize> = VecDeque::with_capacity(window_size);
-    let mut kmer_iterator = rank.qgrams(q as u32, text).into_iter();
-    let mut kmers: FnvHashMap<usize, usize> = FnvHashMap::default();
- 
-    // Init: fill window buffer
-    for _ in 0..window_size {
-        match kmer_iterator.next() {
-            Some(kmer) => window.push_back(kmer),
-            None => break
+impl Write for OutputSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputSink::Plain(w) => w.write(buf),
+            OutputSink::Gzip(w) => w.write(buf),
         }
     }
-    // Count kmers in window
-    for kmer in window.iter() {
-        let n = kmers.entry(*kmer).or_insert(0);
-        *n += 1;
-    }
 
-    let mut idx = 0;
-    loop {
-        let window_complexity = kmers.len() as f64 / window.len() as f64;
-        if window_complexity < threshold {
-            let start = idx;
-            let end = idx + (window.len() - 1 + q as usize);
-            intervals.push(Interval{ start, end });
-        }
-        match kmer_iterator.next() {
-            Some(kmer) => {
-                let prev = window.pop_front().unwrap();
-                window.push_back(kmer);
-                // Update kmer counts: remove 1 from leaving kmer
-                let prev_n = *kmers.get(&prev).unwrap();
-                if prev_n == 1 {
-                    kmers.remove(&prev);
-                } else {
-                    kmers.insert(prev, prev_n-1);
-                }
-                // Update kmer counts: add 1 for entering kmer
-                let next_n = kmers.entry(kmer).or_insert(0);
-                *next_n += 1;
-                // Update index
-                idx += 1;
-            },
-            None => break,
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputSink::Plain(w) => w.flush(),
+            OutputSink::Gzip(w) => w.flush(),
         }
     }
-    return collapse_intervals(intervals);
 }
 
-fn collapse_intervals(intervals: Vec<Interval>) -> Vec<Interval> {
-    let mut collapsed: Vec<Interval> = Vec::new();
-    let mut intervals = intervals.into_iter();
-    if let Some(mut current) = intervals.next() {
-        for interval in intervals {
-            if interval.start < current.end {
-                current.end = interval.end;
-            } else {
-                collapsed.push(current);
-                current = interval;
-            }
+impl OutputSink {
+    /// Flushes the sink and, for gzip output, writes the final gzip
+    /// footer, surfacing any I/O error instead of swallowing it on drop.
+    fn finish(self) -> io::Result<()> {
+        match self {
+            OutputSink::Plain(mut w) => w.flush(),
+            OutputSink::Gzip(w) => w.finish().map(|_| ()),
         }
-        collapsed.push(current);
     }
-    return collapsed;
 }
 
-fn mask_intervals(seq: &[u8], intervals: Vec<Interval>, mask_type: &MaskType) -> Vec<u8> {
-    let mut new_seq: Vec<u8> = Vec::with_capacity(seq.len());
-    let mut last = Interval{start: 0, end: 0};
+/// Opens `output` (or stdout, if absent) for writing, compressing with
+/// gzip when `gzip` is set.
+fn open_writer(output: Option<&str>, gzip: bool) -> OutputSink {
+    let raw: Box<dyn Write> = match output {
+        Some(path) => Box::new(File::create(path).expect("could not create output file")),
+        None => Box::new(io::stdout()),
+    };
+    if gzip {
+        OutputSink::Gzip(GzEncoder::new(raw, Compression::default()))
+    } else {
+        OutputSink::Plain(raw)
+    }
+}
+
+/// Writes a record's collapsed low-complexity intervals as BED3 lines
+/// (0-based, half-open), one per interval.
+fn write_bed<W: Write>(out: &mut W, id: &str, intervals: &[Interval]) {
     for interval in intervals {
-        let intervening = &seq[last.end .. interval.start];
-        new_seq.extend_from_slice(intervening);
-        match *mask_type {
-            MaskType::LowerCase => new_seq.append(&mut lowercase(&seq[interval.start..interval.end])),
-            MaskType::N => new_seq.extend(iter::repeat(b'N').take(interval.end-interval.start)),
-        };
-        // for _ in interval.start..interval.end {
-        //     new_seq.push(b'N');
-        // }
-        last = interval;
+        writeln!(out, "{}\t{}\t{}", id, interval.start, interval.end).unwrap();
+    }
+}
+
+/// Dispatches records to a pool of `threads` workers that each run
+/// `komplexity::unique_kmers`/`mask_sequence`/`low_complexity_intervals`,
+/// then hands results to `emit` on the calling thread in the same order
+/// the records were read. Masking and measuring are per-record and
+/// embarrassingly parallel, so this gives near-linear speedup without
+/// changing output order.
+fn process_records<R, I, E>(records: I, threads: usize, task: Task, options: ComplexityOptions, mut emit: E)
+where
+    R: SeqRecord + Send + 'static,
+    I: Iterator<Item = R> + Send,
+    E: FnMut(&R, RecordOutput),
+{
+    let (work_tx, work_rx) = mpsc::sync_channel::<(usize, R)>(threads * 4);
+    let work_rx = Arc::new(Mutex::new(work_rx));
+    let (result_tx, result_rx) = mpsc::channel::<(usize, R, RecordOutput)>();
+
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            let work_rx = Arc::clone(&work_rx);
+            let result_tx = result_tx.clone();
+            scope.spawn(move || {
+                loop {
+                    let job = work_rx.lock().unwrap().recv();
+                    match job {
+                        Ok((idx, record)) => {
+                            let output = compute_output(&record, task, options);
+                            if result_tx.send((idx, record, output)).is_err() {
+                                break;
+                            }
+                        },
+                        Err(_) => break,
+                    }
+                }
+            });
+        }
+        drop(result_tx);
+
+        scope.spawn(move || {
+            for (idx, record) in records.enumerate() {
+                if work_tx.send((idx, record)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        // Reorder buffer: workers finish out of order, so results are held
+        // here until the next-expected index is available.
+        let mut pending: FnvHashMap<usize, (R, RecordOutput)> = FnvHashMap::default();
+        let mut next = 0;
+        for (idx, record, output) in result_rx {
+            pending.insert(idx, (record, output));
+            while let Some((record, output)) = pending.remove(&next) {
+                emit(&record, output);
+                next += 1;
+            }
+        }
+    });
+}
+
+/// Minimal accessor so `process_records` can work over either
+/// `bio::io::fasta::Record` or `bio::io::fastq::Record` without needing a
+/// shared base type from `bio`.
+trait SeqRecord {
+    fn sequence(&self) -> &[u8];
+    fn identifier(&self) -> &str;
+}
+
+impl SeqRecord for fasta::Record {
+    fn sequence(&self) -> &[u8] { self.seq() }
+    fn identifier(&self) -> &str { self.id() }
+}
+
+impl SeqRecord for fastq::Record {
+    fn sequence(&self) -> &[u8] { self.seq() }
+    fn identifier(&self) -> &str { self.id() }
+}
+
+fn compute_output<R: SeqRecord>(record: &R, task: Task, options: ComplexityOptions) -> RecordOutput {
+    let seq = record.sequence();
+    match task {
+        Task::Mask => RecordOutput::Masked(komplexity::mask_sequence(seq, &options)),
+        Task::Measure => {
+            let length = seq.len();
+            let kmers = komplexity::unique_kmers(seq, options.k);
+            RecordOutput::Measured(format!("{}\t{}\t{}\t{}", record.identifier(), length, kmers, kmers as f64 / length as f64))
+        },
+        Task::Bed => RecordOutput::Bed(komplexity::low_complexity_intervals(seq, &options)),
     }
-    let end = &seq[last.end..];
-    new_seq.extend_from_slice(end);
-    return new_seq;
 }
 
 fn error_exit(msg: &str) {
@@ -283,30 +425,33 @@ fn error_exit(msg: &str) {
     std::process::exit(1);
 }
 
-fn lowercase(seq: &[u8]) -> Vec<u8> {
-    // ACGTRYSWKMBDHVNZ
-    let mut new = Vec::with_capacity(seq.len());
-    for b in seq {
-        let b = match *b {
-            b'A' => b'a',
-            b'C' => b'c',
-            b'G' => b'g',
-            b'T' => b't',
-            b'R' => b'r',
-            b'Y' => b'y',
-            b'S' => b's',
-            b'W' => b'w',
-            b'K' => b'k',
-            b'M' => b'm',
-            b'B' => b'b',
-            b'D' => b'd',
-            b'H' => b'h',
-            b'V' => b'v',
-            b'N' => b'n',
-            b'Z' => b'z',
-            _ => *b,
-        };
-        new.push(b);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone)]
+    struct TestRecord {
+        id: String,
+        seq: Vec<u8>,
     }
-    return new;
-}
\ No newline at end of file
+
+    impl SeqRecord for TestRecord {
+        fn sequence(&self) -> &[u8] { &self.seq }
+        fn identifier(&self) -> &str { &self.id }
+    }
+
+    #[test]
+    fn process_records_preserves_input_order_across_threads() {
+        let records: Vec<TestRecord> = (0..200)
+            .map(|i| TestRecord { id: i.to_string(), seq: b"ACGTACGTACGTACGT".to_vec() })
+            .collect();
+        let expected: Vec<String> = records.iter().map(|r| r.id.clone()).collect();
+
+        let mut seen = Vec::new();
+        process_records(records.into_iter(), 8, Task::Measure, ComplexityOptions::default(), |record, _output| {
+            seen.push(record.identifier().to_string());
+        });
+
+        assert_eq!(seen, expected);
+    }
+}